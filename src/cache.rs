@@ -0,0 +1,453 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard, RwLock},
+};
+use tracing::{info, warn};
+
+/// Upstream revalidation headers captured alongside a cached response, used to issue
+/// conditional `If-None-Match` / `If-Modified-Since` requests instead of a full re-download.
+#[derive(Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Backing store for cached datafile bytes, abstracted so request handling can go through the
+/// same RAM/disk lookup in production and a [`NullCache`] in tests, without entangling
+/// `handle_request` with either backend's storage details.
+#[async_trait]
+pub trait Cache: Send + Sync {
+    /// Returns the cached bytes for `key` if present and younger than `cache_expiry`.
+    async fn get(&self, key: &str, cache_expiry: Duration) -> Option<Bytes> {
+        self.get_within(key, cache_expiry, cache_expiry)
+            .await
+            .map(|(data, _)| data)
+    }
+    /// Returns the cached bytes for `key` if present and younger than `max_age`, along with
+    /// whether they are already past `cache_expiry` (stale but still servable).
+    async fn get_within(
+        &self,
+        key: &str,
+        cache_expiry: Duration,
+        max_age: Duration,
+    ) -> Option<(Bytes, bool)>;
+    /// Returns the cached bytes and upstream validators for `key` regardless of expiry, for
+    /// conditional revalidation of an otherwise-stale entry.
+    async fn get_stale_with_validators(&self, key: &str) -> Option<(Bytes, Validators)>;
+    /// Stores `data` and its upstream validators for `key`, refreshing its expiry.
+    async fn put(&self, key: &str, data: &Bytes, validators: Validators);
+    /// Refreshes an entry's expiry in place, keeping its existing bytes and validators. Used
+    /// after upstream confirms an entry is still current via `304 Not Modified`.
+    async fn touch(&self, key: &str);
+    /// Records that an upstream fetch for `key` just failed.
+    async fn record_failure(&self, key: &str);
+    /// Returns when the most recent failure for `key` was recorded, if any.
+    async fn failed_since(&self, key: &str) -> Option<Instant>;
+    /// Updates the RAM cache's byte budget, evicting immediately if the new budget is smaller.
+    /// Lets an operator shrink or grow the cache via a config reload without a restart.
+    async fn set_capacity_bytes(&self, max_bytes: usize);
+}
+
+struct CacheEntry {
+    data: Bytes,
+    validators: Validators,
+    timestamp: Instant,
+}
+
+/// An LRU-ordered RAM cache bounded by total byte size rather than entry count, since
+/// Grepolis datafiles range from a few KB (`alliances.txt`) to several MB (`towns.txt`).
+struct RamCache {
+    entries: LruCache<String, CacheEntry>,
+    total_bytes: usize,
+    max_bytes: usize,
+}
+
+impl RamCache {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+            total_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Evicts least-recently-used entries until `incoming_size` additional bytes fit under
+    /// `max_bytes`.
+    fn evict_to_fit(&mut self, incoming_size: usize) {
+        while self.total_bytes + incoming_size > self.max_bytes {
+            let Some((evicted_key, evicted_entry)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.total_bytes -= evicted_entry.data.len();
+            info!(
+                cache_key = evicted_key,
+                evicted_bytes = evicted_entry.data.len(),
+                total_bytes = self.total_bytes,
+                "evicted RAM cache entry to stay under byte budget"
+            );
+        }
+    }
+}
+
+/// The on-disk counterpart of a `CacheEntry`: when the bytes were stored, the validators
+/// captured from the upstream response, and an integrity digest of the data, persisted as a
+/// small sidecar file next to the data so all three survive a restart.
+struct DiskMeta {
+    stored_at: SystemTime,
+    validators: Validators,
+    integrity: String,
+}
+
+impl DiskMeta {
+    fn serialize(&self) -> String {
+        let secs = self
+            .stored_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!(
+            "{secs}\n{}\n{}\n{}\n",
+            self.validators.etag.as_deref().unwrap_or(""),
+            self.validators.last_modified.as_deref().unwrap_or(""),
+            self.integrity,
+        )
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        let secs: u64 = lines.next()?.parse().ok()?;
+        let etag = lines.next().unwrap_or("");
+        let last_modified = lines.next().unwrap_or("");
+        let integrity = lines.next().unwrap_or("");
+        Some(Self {
+            stored_at: UNIX_EPOCH + Duration::from_secs(secs),
+            validators: Validators {
+                etag: (!etag.is_empty()).then(|| etag.to_string()),
+                last_modified: (!last_modified.is_empty()).then(|| last_modified.to_string()),
+            },
+            integrity: integrity.to_string(),
+        })
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+fn digest_hex(data: &[u8]) -> String {
+    hex_encode(&Sha256::digest(data))
+}
+
+/// The production [`Cache`] backend: an in-memory LRU cache in front of an on-disk directory,
+/// mirroring the two-tier RAM/disk lookup the server has always done. Disk entries are
+/// content-addressed by a hash of the cache key (rather than the raw `"{server}/{datafile}"`
+/// path) so the filesystem layout doesn't leak upstream URL structure, and are verified against
+/// a stored integrity digest on read so a truncated or corrupted write is never served.
+pub struct TieredCache {
+    ram: RwLock<RamCache>,
+    failed: RwLock<HashMap<String, Instant>>,
+    cache_dir: PathBuf,
+    // Per-key locks serializing disk reads against disk writes, mirroring the weak-map pattern
+    // `main.rs` uses for its in-flight fetch coalescing. Without this, a `read_disk` can land in
+    // the gap between `write_disk_atomic`'s two renames and compare a freshly written data file
+    // against the previous write's `.meta` sidecar, since the pair is only atomic individually.
+    // Entries are never pruned, but since they're keyed by cache_key they're bounded by the same
+    // small, fixed set of server/datafile combinations as the cache itself.
+    disk_locks: StdMutex<HashMap<String, Weak<AsyncMutex<()>>>>,
+}
+
+impl TieredCache {
+    pub async fn new(cache_dir: PathBuf, max_ram_cache_bytes: usize) -> Self {
+        fs::create_dir_all(&cache_dir).await.unwrap();
+        Self {
+            ram: RwLock::new(RamCache::new(max_ram_cache_bytes)),
+            failed: RwLock::new(HashMap::new()),
+            cache_dir,
+            disk_locks: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Acquires the per-key disk lock for `key`, so the read or write that follows can't
+    /// interleave with another disk operation on the same key.
+    async fn lock_disk(&self, key: &str) -> OwnedMutexGuard<()> {
+        // Resolved inside a block so the `StdMutex` guard's lexical scope ends before the
+        // `.await` below — an explicit `drop()` isn't enough for the Send analysis on an
+        // `#[async_trait]` future to see the guard as gone.
+        let lock = {
+            let mut locks = self.disk_locks.lock().unwrap();
+            if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+                lock
+            } else {
+                let lock = Arc::new(AsyncMutex::new(()));
+                locks.insert(key.to_string(), Arc::downgrade(&lock));
+                lock
+            }
+        };
+        lock.lock_owned().await
+    }
+
+    /// Reads the entry for `key` from RAM regardless of expiry, bumping its LRU recency.
+    async fn read_ram(&self, key: &str) -> Option<(Bytes, Validators, Instant)> {
+        let mut ram = self.ram.write().await;
+        ram.entries.get(key).map(|entry| {
+            (
+                entry.data.clone(),
+                entry.validators.clone(),
+                entry.timestamp,
+            )
+        })
+    }
+
+    fn disk_path(&self, key: &str) -> PathBuf {
+        self.cache_dir.join(digest_hex(key.as_bytes()))
+    }
+
+    fn meta_path(&self, key: &str) -> PathBuf {
+        self.cache_dir
+            .join(format!("{}.meta", digest_hex(key.as_bytes())))
+    }
+
+    fn tmp_path(&self, key: &str, suffix: &str) -> PathBuf {
+        self.cache_dir
+            .join(format!("{}.{suffix}.tmp", digest_hex(key.as_bytes())))
+    }
+
+    async fn read_disk(&self, key: &str) -> Option<(Bytes, DiskMeta)> {
+        let _guard = self.lock_disk(key).await;
+        let meta_text = fs::read_to_string(self.meta_path(key)).await.ok()?;
+        let meta = DiskMeta::parse(&meta_text)?;
+        let data = fs::read(self.disk_path(key)).await.ok()?;
+        if digest_hex(&data) != meta.integrity {
+            warn!(
+                cache_key = key,
+                "disk cache entry failed integrity check, evicting corrupted copy"
+            );
+            self.remove_disk(key).await;
+            return None;
+        }
+        Some((Bytes::from(data), meta))
+    }
+
+    async fn remove_disk(&self, key: &str) {
+        fs::remove_file(self.disk_path(key)).await.ok();
+        fs::remove_file(self.meta_path(key)).await.ok();
+    }
+
+    async fn put_ram(&self, key: &str, data: &Bytes, validators: Validators, timestamp: Instant) {
+        let mut ram = self.ram.write().await;
+        let incoming_size = data.len();
+        ram.evict_to_fit(incoming_size);
+
+        let previous = ram.entries.put(
+            key.to_string(),
+            CacheEntry {
+                data: data.clone(),
+                validators,
+                timestamp,
+            },
+        );
+        if let Some(previous) = previous {
+            ram.total_bytes -= previous.data.len();
+        }
+        ram.total_bytes += incoming_size;
+    }
+
+    /// Writes `data` and then `meta_text` for `key`, each via a temp file that's atomically
+    /// renamed into place, so a crash mid-write never leaves a partial file. The two renames are
+    /// separate: a crash or failed write between them leaves new data beside the previous
+    /// `.meta`, which `read_disk`'s integrity check rejects. Callers hold the per-key disk lock,
+    /// so a concurrent read never sees that in-between state.
+    async fn write_disk_atomic(&self, key: &str, data: &Bytes, meta_text: &str) {
+        let data_tmp = self.tmp_path(key, "data");
+        if fs::write(&data_tmp, data).await.is_err() {
+            return;
+        }
+        if fs::rename(&data_tmp, self.disk_path(key)).await.is_err() {
+            fs::remove_file(&data_tmp).await.ok();
+            return;
+        }
+
+        let meta_tmp = self.tmp_path(key, "meta");
+        if fs::write(&meta_tmp, meta_text).await.is_err() {
+            return;
+        }
+        if fs::rename(&meta_tmp, self.meta_path(key)).await.is_err() {
+            fs::remove_file(&meta_tmp).await.ok();
+        }
+    }
+
+    async fn put_disk(&self, key: &str, data: &Bytes, validators: &Validators) {
+        let _guard = self.lock_disk(key).await;
+        let meta = DiskMeta {
+            stored_at: SystemTime::now(),
+            validators: validators.clone(),
+            integrity: digest_hex(data),
+        };
+        self.write_disk_atomic(key, data, &meta.serialize()).await;
+    }
+
+    async fn touch_disk(&self, key: &str) {
+        let _guard = self.lock_disk(key).await;
+        let Ok(text) = fs::read_to_string(self.meta_path(key)).await else {
+            return;
+        };
+        let Some(mut meta) = DiskMeta::parse(&text) else {
+            return;
+        };
+        meta.stored_at = SystemTime::now();
+        let meta_tmp = self.tmp_path(key, "meta");
+        if fs::write(&meta_tmp, meta.serialize()).await.is_ok() {
+            fs::rename(&meta_tmp, self.meta_path(key)).await.ok();
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for TieredCache {
+    async fn get_within(
+        &self,
+        key: &str,
+        cache_expiry: Duration,
+        max_age: Duration,
+    ) -> Option<(Bytes, bool)> {
+        if let Some((data, _, timestamp)) = self.read_ram(key).await {
+            let elapsed = timestamp.elapsed();
+            if elapsed < max_age {
+                return Some((data, elapsed >= cache_expiry));
+            }
+        }
+        if let Some((data, meta)) = self.read_disk(key).await {
+            if let Ok(elapsed) = meta.stored_at.elapsed() {
+                if elapsed < max_age {
+                    // Keep the disk copy's age in RAM, so it expires on the same schedule.
+                    let stored_at = Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now);
+                    self.put_ram(key, &data, meta.validators, stored_at).await;
+                    return Some((data, elapsed >= cache_expiry));
+                }
+            }
+        }
+        None
+    }
+
+    async fn get_stale_with_validators(&self, key: &str) -> Option<(Bytes, Validators)> {
+        if let Some((data, validators, _)) = self.read_ram(key).await {
+            return Some((data, validators));
+        }
+        self.read_disk(key)
+            .await
+            .map(|(data, meta)| (data, meta.validators))
+    }
+
+    async fn put(&self, key: &str, data: &Bytes, validators: Validators) {
+        self.put_disk(key, data, &validators).await;
+        self.put_ram(key, data, validators, Instant::now()).await;
+    }
+
+    async fn touch(&self, key: &str) {
+        if let Some((data, validators, _)) = self.read_ram(key).await {
+            self.put_ram(key, &data, validators, Instant::now()).await;
+        }
+        self.touch_disk(key).await;
+    }
+
+    async fn record_failure(&self, key: &str) {
+        let mut failed = self.failed.write().await;
+        failed.insert(key.to_string(), Instant::now());
+    }
+
+    async fn failed_since(&self, key: &str) -> Option<Instant> {
+        let failed = self.failed.read().await;
+        failed.get(key).copied()
+    }
+
+    async fn set_capacity_bytes(&self, max_bytes: usize) {
+        let mut ram = self.ram.write().await;
+        ram.max_bytes = max_bytes;
+        ram.evict_to_fit(0);
+    }
+}
+
+/// A [`Cache`] that never stores anything, letting integration tests exercise the
+/// upstream-fetch and failure paths deterministically without touching `./cache`. Only ever
+/// constructed under `#[cfg(test)]`, hence the `allow`.
+#[derive(Default)]
+#[allow(dead_code)]
+pub struct NullCache;
+
+#[async_trait]
+impl Cache for NullCache {
+    async fn get_within(
+        &self,
+        _key: &str,
+        _cache_expiry: Duration,
+        _max_age: Duration,
+    ) -> Option<(Bytes, bool)> {
+        None
+    }
+
+    async fn get_stale_with_validators(&self, _key: &str) -> Option<(Bytes, Validators)> {
+        None
+    }
+
+    async fn put(&self, _key: &str, _data: &Bytes, _validators: Validators) {}
+
+    async fn touch(&self, _key: &str) {}
+
+    async fn record_failure(&self, _key: &str) {}
+
+    async fn failed_since(&self, _key: &str) -> Option<Instant> {
+        None
+    }
+
+    async fn set_capacity_bytes(&self, _max_bytes: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `NullCache` must never turn a write into a later hit, so every request the handler
+    /// makes against it deterministically falls through to the upstream-fetch path.
+    #[tokio::test]
+    async fn null_cache_never_serves_a_write_back() {
+        let cache = NullCache;
+        let key = "de1/towns.txt";
+        cache
+            .put(key, &Bytes::from_static(b"data"), Validators::default())
+            .await;
+
+        assert!(cache.get(key, Duration::from_mins(1)).await.is_none());
+        assert!(cache
+            .get_within(key, Duration::from_mins(1), Duration::from_mins(10))
+            .await
+            .is_none());
+        assert!(cache.get_stale_with_validators(key).await.is_none());
+    }
+
+    /// Likewise, a recorded failure must never stick, so the failure path is retried on every
+    /// request rather than short-circuited by a cached `BAD_GATEWAY`.
+    #[tokio::test]
+    async fn null_cache_never_remembers_a_failure() {
+        let cache = NullCache;
+        let key = "de1/towns.txt";
+        cache.record_failure(key).await;
+
+        assert!(cache.failed_since(key).await.is_none());
+    }
+}
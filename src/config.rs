@@ -0,0 +1,74 @@
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// On-disk shape of the config file; see [`Config`] for the parsed, ready-to-use form.
+#[derive(Deserialize)]
+struct RawConfig {
+    listen_address: String,
+    upstream_domain: String,
+    cache_expiry_secs: u64,
+    stale_max_secs: u64,
+    max_ram_cache_bytes: usize,
+    max_concurrent_refreshes: usize,
+    datafile_whitelist: Vec<String>,
+    server_regex: String,
+}
+
+/// Runtime-reloadable operational parameters that used to be compile-time constants: the
+/// upstream host, listen address, cache sizing and expiry windows, the datafile whitelist, and
+/// the `{server}` path validation regex. `main.rs` holds this behind an `ArcSwap` and reloads it
+/// from disk on `SIGHUP`, so operators can change them without a rebuild or restart. The one
+/// exception is `listen_address`: the listener is bound once at startup, and a reload does not
+/// rebind it.
+#[derive(Clone)]
+pub struct Config {
+    pub listen_address: SocketAddr,
+    pub upstream_domain: String,
+    pub cache_expiry: Duration,
+    pub stale_max: Duration,
+    pub max_ram_cache_bytes: usize,
+    pub max_concurrent_refreshes: usize,
+    pub datafile_whitelist: Vec<String>,
+    pub server_regex: Regex,
+}
+
+impl Config {
+    pub fn is_datafile_allowed(&self, datafile: &str) -> bool {
+        self.datafile_whitelist
+            .iter()
+            .any(|allowed| allowed == datafile)
+    }
+}
+
+impl TryFrom<RawConfig> for Config {
+    type Error = String;
+
+    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            listen_address: raw
+                .listen_address
+                .parse()
+                .map_err(|err| format!("invalid listen_address: {err}"))?,
+            upstream_domain: raw.upstream_domain,
+            cache_expiry: Duration::from_secs(raw.cache_expiry_secs),
+            stale_max: Duration::from_secs(raw.stale_max_secs),
+            max_ram_cache_bytes: raw.max_ram_cache_bytes,
+            max_concurrent_refreshes: raw.max_concurrent_refreshes,
+            datafile_whitelist: raw.datafile_whitelist,
+            server_regex: Regex::new(&raw.server_regex)
+                .map_err(|err| format!("invalid server_regex: {err}"))?,
+        })
+    }
+}
+
+/// Reads and validates the config file at `path`.
+pub async fn load(path: &Path) -> Result<Config, String> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    let raw: RawConfig = toml::from_str(&text)
+        .map_err(|err| format!("failed to parse {}: {err}", path.display()))?;
+    Config::try_from(raw)
+}
@@ -1,5 +1,9 @@
 #![warn(clippy::pedantic)]
 
+mod cache;
+mod config;
+
+use arc_swap::ArcSwap;
 use axum::{
     body::Body,
     extract::Path,
@@ -9,25 +13,28 @@ use axum::{
     Extension, Router,
 };
 use bytes::Bytes;
-use lazy_static::lazy_static;
-use regex::Regex;
-use reqwest::{redirect::Policy, Client};
+use cache::{Cache, TieredCache, Validators};
+use config::Config;
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    redirect::Policy,
+    Client, StatusCode as UpstreamStatusCode,
+};
 use std::{
     collections::HashMap,
     path::PathBuf,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
 };
-use tokio::{fs, sync::RwLock};
-use tracing::info;
+use tracing::{error, info};
 
-const CACHE_EXPIRY: Duration = Duration::from_secs(15 * 60); // 15 minutes
-const MAX_FILES_IN_RAM_CACHE: usize = 25;
-lazy_static! {
-    static ref SERVER_REGEX: Regex = Regex::new(r"^[a-zA-Z]{2}\d{1,3}$").unwrap();
-    static ref DATAFILE_WHITELIST: Vec<&'static str> =
-        vec!["players.txt", "towns.txt", "alliances.txt", "islands.txt"];
-}
+const CONFIG_PATH: &str = "config.toml";
 
 #[tokio::main]
 async fn main() {
@@ -36,30 +43,62 @@ async fn main() {
     // let subscriber = tracing_subscriber::fmt().json().finish();
     // tracing::subscriber::set_global_default(subscriber).unwrap();
 
+    let config_path = PathBuf::from(CONFIG_PATH);
+    let config = config::load(&config_path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to load {}: {err}", config_path.display()));
+    let listen_address = config.listen_address;
+
     // Initialize the cache and HTTP client
-    let app_state = Arc::new(AppState::new().await);
+    let app_state = Arc::new(AppState::new(config).await);
+    spawn_config_reloader(Arc::clone(&app_state), config_path);
 
     // Build our application with a route
     let app = Router::new()
         .route("/{server}/{datafile}", get(handle_request))
         .layer(Extension(app_state));
 
-    // run our app with hyper, listening globally on port 3000
-    let listen_address = "[::]:3000";
+    // run our app with hyper, listening on the configured address
     info!("listening on {listen_address}");
     let listener = tokio::net::TcpListener::bind(listen_address).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
 
 struct AppState {
-    cache: RwLock<HashMap<String, CacheEntry>>,
-    failed_cache: RwLock<HashMap<String, Instant>>,
+    cache: Box<dyn Cache>,
     client: Client,
-    cache_dir: PathBuf,
+    config: ArcSwap<Config>,
+    // Tracks upstream fetches that are currently in flight, keyed by cache_key, so that
+    // concurrent requests for the same datafile coalesce into a single upstream GET, whether
+    // that fetch was triggered by a cold cache miss or a stale-while-revalidate refresh. The
+    // value is the sending half of a `watch` channel (not a `Notify`) so a follower that
+    // subscribes after the leader has already finished still observes the completed state
+    // instead of waiting on a signal that already fired.
+    inflight: StdMutex<HashMap<String, Weak<watch::Sender<bool>>>>,
+    // Bounds how many stale-while-revalidate background refreshes may run concurrently.
+    // `refresh_permits_size` tracks the permit count the semaphore was last resized to, so a
+    // SIGHUP reload can compute the delta and grow or shrink it to match the new config.
+    refresh_permits: Arc<Semaphore>,
+    refresh_permits_size: AtomicUsize,
+    // A shrink that `Semaphore::forget_permits` couldn't fully apply immediately (because the
+    // permits it would forget were checked out, not available) because of a shortfall. Each
+    // `RefreshPermit` pays this down by one on release, by forgetting itself instead of
+    // returning its permit to the semaphore, until the shrink has fully taken effect.
+    refresh_permits_pending_shrink: AtomicUsize,
 }
 
 impl AppState {
-    async fn new() -> Self {
+    async fn new(config: Config) -> Self {
+        // Set up the cache directory
+        let cache_dir: PathBuf = "./cache".into();
+        let cache = TieredCache::new(cache_dir, config.max_ram_cache_bytes).await;
+        Self::with_cache(config, Box::new(cache))
+    }
+
+    /// Builds state around an explicit [`Cache`] backend instead of the production
+    /// [`TieredCache`], letting tests inject [`cache::NullCache`] to drive `handle_request`
+    /// through its upstream-fetch and failure paths deterministically, without touching `./cache`.
+    fn with_cache(config: Config, cache: Box<dyn Cache>) -> Self {
         // Create the HTTP client with custom headers
         let client = Client::builder()
             .user_agent("YourCustomUserAgent")
@@ -68,163 +107,436 @@ impl AppState {
             .redirect(Policy::none())
             .build()
             .unwrap();
-        // Set up the cache directory
-        let cache_dir = "./cache".into();
-        fs::create_dir_all(&cache_dir).await.unwrap();
+        let refresh_permits = Arc::new(Semaphore::new(config.max_concurrent_refreshes));
+        let refresh_permits_size = AtomicUsize::new(config.max_concurrent_refreshes);
         Self {
-            cache: RwLock::new(HashMap::new()),
-            failed_cache: RwLock::new(HashMap::new()),
+            cache,
             client,
-            cache_dir,
+            config: ArcSwap::from_pointee(config),
+            inflight: StdMutex::new(HashMap::new()),
+            refresh_permits,
+            refresh_permits_size,
+            refresh_permits_pending_shrink: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Watches for `SIGHUP` and reloads the config file in place, swapping in the new snapshot
+/// atomically without dropping in-flight requests or the warm cache.
+fn spawn_config_reloader(state: Arc<AppState>, config_path: PathBuf) {
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!("failed to install SIGHUP handler: {err}");
+                return;
+            }
+        };
+        loop {
+            hangup.recv().await;
+            match config::load(&config_path).await {
+                Ok(new_config) => {
+                    state
+                        .cache
+                        .set_capacity_bytes(new_config.max_ram_cache_bytes)
+                        .await;
+                    resize_refresh_permits(&state, new_config.max_concurrent_refreshes);
+                    state.config.store(Arc::new(new_config));
+                    info!(path = %config_path.display(), "reloaded config on SIGHUP");
+                }
+                Err(err) => {
+                    error!(path = %config_path.display(), %err, "failed to reload config on SIGHUP, keeping previous config");
+                }
+            }
         }
+    });
+}
+
+/// Grows or shrinks `refresh_permits` to match a reloaded `max_concurrent_refreshes`, the same
+/// way `set_capacity_bytes` lets the RAM cache budget track a reload without a restart. Shrinking
+/// can only forget permits that are currently available; `forget_permits` reports how many of
+/// those it actually forgot, and anything it couldn't is queued in `refresh_permits_pending_shrink`
+/// so the held permits pay down the rest as they're released (see [`RefreshPermit`]).
+fn resize_refresh_permits(state: &AppState, new_size: usize) {
+    let old_size = state.refresh_permits_size.swap(new_size, Ordering::SeqCst);
+    match new_size.cmp(&old_size) {
+        std::cmp::Ordering::Greater => state.refresh_permits.add_permits(new_size - old_size),
+        std::cmp::Ordering::Less => {
+            let shrink_by = old_size - new_size;
+            let forgotten = state.refresh_permits.forget_permits(shrink_by);
+            let shortfall = shrink_by - forgotten;
+            if shortfall > 0 {
+                state
+                    .refresh_permits_pending_shrink
+                    .fetch_add(shortfall, Ordering::SeqCst);
+            }
+        }
+        std::cmp::Ordering::Equal => {}
     }
 }
 
-struct CacheEntry {
-    data: Bytes,
-    timestamp: Instant,
+/// A checked-out slot in `refresh_permits`. Released normally on drop, unless a reload shrank
+/// `refresh_permits` while this permit was checked out and the shrink is still short of its
+/// target (`refresh_permits_pending_shrink > 0`) — in that case this permit is forgotten
+/// instead of returned to the semaphore, paying down one unit of the shortfall so the effective
+/// cap reaches the configured value as in-flight refreshes finish rather than never.
+struct RefreshPermit {
+    state: Arc<AppState>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for RefreshPermit {
+    fn drop(&mut self) {
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+        let mut shortfall = state_pending_shrink(&self.state);
+        loop {
+            if shortfall == 0 {
+                return; // `permit`'s own Drop returns it to the semaphore.
+            }
+            match self.state.refresh_permits_pending_shrink.compare_exchange(
+                shortfall,
+                shortfall - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    permit.forget();
+                    return;
+                }
+                Err(actual) => shortfall = actual,
+            }
+        }
+    }
+}
+
+fn state_pending_shrink(state: &AppState) -> usize {
+    state.refresh_permits_pending_shrink.load(Ordering::SeqCst)
+}
+
+/// Acquires a slot in `refresh_permits`, or `None` if the configured concurrency limit for
+/// background refreshes is already reached.
+fn try_acquire_refresh_permit(state: &Arc<AppState>) -> Option<RefreshPermit> {
+    let permit = Arc::clone(&state.refresh_permits).try_acquire_owned().ok()?;
+    Some(RefreshPermit {
+        state: Arc::clone(state),
+        permit: Some(permit),
+    })
+}
+
+/// Whether the current request is responsible for fetching upstream (`Leader`) or should
+/// instead wait for another in-flight request to finish and re-read the cache (`Follower`).
+enum InflightRole {
+    Leader(InflightGuard),
+    Follower(watch::Receiver<bool>),
+}
+
+/// Held by the leader of an in-flight fetch. Removes the `inflight` map entry and marks the
+/// fetch done on drop, whether it succeeded, failed, or the leader task panicked.
+struct InflightGuard {
+    state: Arc<AppState>,
+    cache_key: String,
+    done: Arc<watch::Sender<bool>>,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        state_remove_inflight(&self.state, &self.cache_key);
+        // Unlike `Notify::notify_waiters`, this updates state a late subscriber can still
+        // observe: a follower that calls `subscribe()` after this send sees `true` immediately
+        // instead of waiting on an edge that already passed.
+        let _ = self.done.send(true);
+    }
+}
+
+fn state_remove_inflight(state: &Arc<AppState>, cache_key: &str) {
+    let mut inflight = state.inflight.lock().unwrap();
+    inflight.remove(cache_key);
+}
+
+/// Atomically becomes the leader for `cache_key` if no fetch is currently in flight, or
+/// returns a receiver subscribed to the existing leader's completion so the caller can wait on
+/// it instead of issuing a duplicate upstream request.
+fn join_inflight(state: &Arc<AppState>, cache_key: &str) -> InflightRole {
+    let mut inflight = state.inflight.lock().unwrap();
+    if let Some(done) = inflight.get(cache_key).and_then(Weak::upgrade) {
+        return InflightRole::Follower(done.subscribe());
+    }
+    let (done, _) = watch::channel(false);
+    let done = Arc::new(done);
+    inflight.insert(cache_key.to_string(), Arc::downgrade(&done));
+    drop(inflight);
+    InflightRole::Leader(InflightGuard {
+        state: Arc::clone(state),
+        cache_key: cache_key.to_string(),
+        done,
+    })
 }
 
 async fn handle_request(
     Path((server, datafile)): Path<(String, String)>,
     Extension(state): Extension<Arc<AppState>>,
 ) -> Response<Body> {
+    // Snapshot the current config for the lifetime of this request, so a concurrent SIGHUP
+    // reload can't change the rules partway through.
+    let config = state.config.load_full();
+
     // Validate the server parameter
-    if !SERVER_REGEX.is_match(&server) {
+    if !config.server_regex.is_match(&server) {
         return StatusCode::NOT_FOUND.into_response();
     }
     // Validate the datafile parameter
-    if !DATAFILE_WHITELIST.contains(&datafile.as_str()) {
+    if !config.is_datafile_allowed(&datafile) {
         return StatusCode::NOT_FOUND.into_response();
     }
 
     let cache_key = format!("{server}/{datafile}");
 
     // Check if there is a cached failure
-    if let Some(failed_response) = get_from_failed_cache(&state, &cache_key).await {
-        if failed_response.elapsed() < CACHE_EXPIRY {
+    if let Some(failed_response) = state.cache.failed_since(&cache_key).await {
+        if failed_response.elapsed() < config.cache_expiry {
             info!(result = "fail", reason = "cache", server, datafile);
             return StatusCode::BAD_GATEWAY.into_response();
         }
     }
 
-    // Check if response is cached in RAM
-    if let Some(data) = get_from_ram_cache(&state, &cache_key).await {
-        info!(result = "success", reason = "ram cache", server, datafile);
-        return (StatusCode::OK, data).into_response();
-    }
-    // Check if response is cached on disk
-    if let Some(data) = get_from_disk_cache(&state, &cache_key).await {
-        info!(result = "success", reason = "file cache", server, datafile);
-        update_ram_cache(&state, &cache_key, &data).await;
+    // Check if response is cached (RAM or disk), serving a stale-but-within-window copy
+    // immediately while a background task refreshes it.
+    if let Some((data, is_stale)) = state
+        .cache
+        .get_within(&cache_key, config.cache_expiry, config.stale_max)
+        .await
+    {
+        if is_stale {
+            info!(result = "success", reason = "stale", server, datafile);
+            spawn_background_refresh(&state, server, datafile, cache_key);
+            return stale_response(data);
+        }
+        info!(result = "success", reason = "cache", server, datafile);
         return (StatusCode::OK, data).into_response();
     }
-    // Fetch from the external API
-    if let Some(data) = fetch_and_cache(&state, &server, &datafile, &cache_key).await {
-        info!(result = "success", reason = "upstream", server, datafile);
-        (StatusCode::OK, data).into_response()
-    } else {
-        info!(result = "fail", reason = "upstream", server, datafile);
-        StatusCode::BAD_GATEWAY.into_response()
-    }
-}
 
-async fn get_from_ram_cache(state: &Arc<AppState>, cache_key: &str) -> Option<Bytes> {
-    let cache = state.cache.read().await;
-    if let Some(entry) = cache.get(cache_key) {
-        if entry.timestamp.elapsed() < CACHE_EXPIRY {
-            // Cache hit
-            return Some(entry.data.clone());
+    // Fetch from the external API, coalescing concurrent misses for the same cache_key into
+    // a single upstream request.
+    match join_inflight(&state, &cache_key) {
+        InflightRole::Leader(guard) => {
+            let result = fetch_and_cache(
+                &state,
+                &config.upstream_domain,
+                &server,
+                &datafile,
+                &cache_key,
+            )
+            .await;
+            drop(guard); // removes the inflight entry and wakes followers
+            if let Some(data) = result {
+                info!(result = "success", reason = "upstream", server, datafile);
+                (StatusCode::OK, data).into_response()
+            } else {
+                info!(result = "fail", reason = "upstream", server, datafile);
+                StatusCode::BAD_GATEWAY.into_response()
+            }
         }
-    }
-    None
-}
-
-async fn get_from_disk_cache(state: &Arc<AppState>, cache_key: &str) -> Option<Bytes> {
-    let cache_path = state.cache_dir.join(cache_key);
-    if let Ok(metadata) = fs::metadata(&cache_path).await {
-        if metadata.is_file() {
-            if let Ok(modified) = metadata.modified() {
-                if let Ok(elapsed) = modified.elapsed() {
-                    if elapsed < CACHE_EXPIRY {
-                        if let Ok(data) = fs::read(&cache_path).await {
-                            return Some(Bytes::from(data));
-                        }
-                    }
-                }
+        InflightRole::Follower(mut done) => {
+            let _ = done.wait_for(|done| *done).await;
+            if let Some(data) = state.cache.get(&cache_key, config.cache_expiry).await {
+                info!(result = "success", reason = "coalesced", server, datafile);
+                (StatusCode::OK, data).into_response()
+            } else {
+                info!(result = "fail", reason = "coalesced", server, datafile);
+                StatusCode::BAD_GATEWAY.into_response()
             }
         }
     }
-    None
 }
 
-async fn get_from_failed_cache(state: &Arc<AppState>, cache_key: &str) -> Option<Instant> {
-    let cache = state.failed_cache.read().await;
-    cache.get(cache_key).copied()
+/// Builds the response for a stale-but-servable cache hit, marking it so clients (and us, in
+/// logs) can tell the bytes are being refreshed in the background rather than current.
+fn stale_response(data: Bytes) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("X-Cache-Status", "stale")
+        .body(Body::from(data))
+        .unwrap()
 }
 
-async fn update_failed_cache(state: &Arc<AppState>, cache_key: &str) {
-    let mut cache = state.failed_cache.write().await;
-    cache.insert(cache_key.to_string(), Instant::now());
+/// Kicks off a background refresh of `cache_key`, unless one is already running (either a
+/// refresh from an earlier stale hit, or a foreground fetch for a cold miss) — the shared
+/// `inflight` map coalesces both cases down to a single upstream request per key.
+fn spawn_background_refresh(
+    state: &Arc<AppState>,
+    server: String,
+    datafile: String,
+    cache_key: String,
+) {
+    let InflightRole::Leader(guard) = join_inflight(state, &cache_key) else {
+        return;
+    };
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        let Some(_permit) = try_acquire_refresh_permit(&state) else {
+            // Too many refreshes already in flight; the stale copy keeps serving until one
+            // of them frees up.
+            return;
+        };
+        let upstream_domain = state.config.load().upstream_domain.clone();
+        fetch_and_cache(&state, &upstream_domain, &server, &datafile, &cache_key).await;
+        drop(guard); // removes the inflight entry and wakes any coalesced waiters
+    });
 }
 
 async fn fetch_and_cache(
     state: &Arc<AppState>,
+    upstream_domain: &str,
     server: &str,
     datafile: &str,
     cache_key: &str,
 ) -> Option<Bytes> {
-    let url = format!("https://{server}.grepolis.com/data/{datafile}");
+    let url = format!("https://{server}.{upstream_domain}/data/{datafile}");
+
+    // If a stale copy is on hand, ask upstream to confirm it's unchanged instead of paying
+    // for a full re-download of files like `towns.txt` that mostly don't change.
+    let stale_entry = state.cache.get_stale_with_validators(cache_key).await;
+    let mut request = state.client.get(&url);
+    if let Some((_, validators)) = &stale_entry {
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
 
     // Perform the HTTP GET request with custom headers
-    let Ok(response) = state.client.get(&url).send().await else {
-        update_failed_cache(state, cache_key).await;
+    let Ok(response) = request.send().await else {
+        state.cache.record_failure(cache_key).await;
         return None;
     };
 
+    if response.status() == UpstreamStatusCode::NOT_MODIFIED {
+        let Some((data, _)) = stale_entry else {
+            // Upstream claims nothing changed, but we have nothing stale to compare against.
+            state.cache.record_failure(cache_key).await;
+            return None;
+        };
+        state.cache.touch(cache_key).await;
+        return Some(data);
+    }
+
     if !response.status().is_success() {
-        update_failed_cache(state, cache_key).await;
+        state.cache.record_failure(cache_key).await;
         return None;
     }
 
+    let validators = Validators {
+        etag: header_str(&response, ETAG),
+        last_modified: header_str(&response, LAST_MODIFIED),
+    };
+
     let Ok(data) = response.bytes().await else {
-        update_failed_cache(state, cache_key).await;
+        state.cache.record_failure(cache_key).await;
         return None;
     };
 
-    // Update caches
-    update_disk_cache(state, cache_key, &data).await;
-    update_ram_cache(state, cache_key, &data).await;
+    // Update cache
+    state.cache.put(cache_key, &data, validators).await;
     Some(data)
 }
 
-async fn update_ram_cache(state: &Arc<AppState>, cache_key: &str, data: &Bytes) {
-    let mut cache = state.cache.write().await;
-    // If the cache exceeds MAX_FILES_IN_RAM_CACHE, remove the least recently used entry
-    if cache.len() >= MAX_FILES_IN_RAM_CACHE {
-        // Simple LRU implementation
-        if let Some(oldest_key) = cache
-            .iter()
-            .min_by_key(|entry| entry.1.timestamp)
-            .map(|(k, _)| k.clone())
-        {
-            cache.remove(&oldest_key);
+fn header_str(response: &reqwest::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::NullCache;
+    use regex::Regex;
+    use std::time::Duration;
+
+    fn test_config() -> Config {
+        Config {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            // Reserved for documentation/testing (RFC 2606); never resolves, so every request
+            // deterministically fails upstream instead of depending on network availability.
+            upstream_domain: "example.invalid".to_string(),
+            cache_expiry: Duration::from_mins(1),
+            stale_max: Duration::from_mins(10),
+            max_ram_cache_bytes: 1024,
+            max_concurrent_refreshes: 1,
+            datafile_whitelist: vec!["towns.txt".to_string()],
+            server_regex: Regex::new("^[a-z0-9]+$").unwrap(),
         }
     }
-    // Insert the new entry
-    cache.insert(
-        cache_key.to_string(),
-        CacheEntry {
-            data: data.clone(),
-            timestamp: Instant::now(),
-        },
-    );
-}
 
-async fn update_disk_cache(state: &Arc<AppState>, cache_key: &str, data: &Bytes) {
-    let cache_path = state.cache_dir.join(cache_key);
-    if let Some(parent) = cache_path.parent() {
-        fs::create_dir_all(parent).await.ok();
+    fn test_state_with(config: Config) -> Arc<AppState> {
+        Arc::new(AppState::with_cache(config, Box::new(NullCache)))
+    }
+
+    async fn request(state: &Arc<AppState>) -> Response<Body> {
+        handle_request(
+            Path(("de1".to_string(), "towns.txt".to_string())),
+            Extension(Arc::clone(state)),
+        )
+        .await
+    }
+
+    /// With [`NullCache`] standing in for [`cache::TieredCache`], every request must take the
+    /// upstream-fetch path and, since the upstream host never resolves, the failure path too —
+    /// and because `NullCache` never remembers a failure, a second request retries upstream
+    /// instead of being short-circuited by a cached `BAD_GATEWAY`.
+    #[tokio::test]
+    async fn handle_request_retries_upstream_on_each_call_with_null_cache() {
+        let state = test_state_with(test_config());
+
+        let first = request(&state).await;
+        assert_eq!(first.status(), StatusCode::BAD_GATEWAY);
+
+        let second = request(&state).await;
+        assert_eq!(second.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    /// An unknown datafile is rejected before ever consulting the cache or upstream.
+    #[tokio::test]
+    async fn handle_request_rejects_datafile_outside_whitelist() {
+        let state = test_state_with(test_config());
+
+        let response = handle_request(
+            Path(("de1".to_string(), "not-allowed.txt".to_string())),
+            Extension(state),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// A shrink that finds most permits checked out can only forget the few that are available;
+    /// the shortfall must be queued and paid down as the held permits are released, rather than
+    /// silently dropped — leaving the semaphore permanently oversized relative to the config.
+    #[tokio::test]
+    async fn shrinking_refresh_permits_while_held_catches_up_on_release() {
+        let mut config = test_config();
+        config.max_concurrent_refreshes = 4;
+        let state = test_state_with(config);
+
+        // Check out 3 of the 4 permits, leaving only 1 available to forget outright.
+        let p1 = try_acquire_refresh_permit(&state).unwrap();
+        let p2 = try_acquire_refresh_permit(&state).unwrap();
+        let p3 = try_acquire_refresh_permit(&state).unwrap();
+
+        resize_refresh_permits(&state, 2);
+        assert_eq!(state_pending_shrink(&state), 1);
+
+        drop(p1);
+        drop(p2);
+        drop(p3);
+
+        assert_eq!(state_pending_shrink(&state), 0);
+        assert_eq!(state.refresh_permits.available_permits(), 2);
     }
-    fs::write(cache_path, data).await.ok();
 }